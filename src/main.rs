@@ -1,52 +1,174 @@
 use ::rocket::async_main;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::{path::PathBuf, time::Duration};
 
-use clap::Parser;
-use espflash::{elf::FirmwareImageBuilder, Chip, FlashSize, PartitionTable};
-use rocket::{response::content, State};
+use clap::{Parser, ValueEnum};
+use espflash::{
+    elf::FirmwareImageBuilder, Chip, FlashFrequency, FlashMode, FlashSize, PartitionTable,
+};
+use rocket::{http::ContentType, response::content, State};
 
 #[macro_use]
 extern crate rocket;
 
+/// Value that marks a per-`--chip` override slot as "use the default", so a caller can target a
+/// later `--chip` without the override silently applying to the first one instead.
+const SKIP: &str = "-";
+
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// chip name
-    #[arg(short, long)]
-    chip: Chip,
+    /// chip name, repeat alongside --elf for each build to serve
+    #[arg(short, long = "chip", required = true)]
+    chip: Vec<Chip>,
+
+    /// path to firmware elf, one per --chip in the same order
+    #[arg(short, long = "elf", required = true)]
+    elf: Vec<PathBuf>,
+
+    /// path to bootloader, either omitted or one per --chip in the same order ("-" to skip a chip)
+    #[arg(short, long = "bootloader")]
+    bootloader: Vec<String>,
+
+    /// path to partition table csv, either omitted or one per --chip in the same order ("-" to skip a chip)
+    #[arg(short, long = "partition-table")]
+    partition_table: Vec<String>,
+
+    /// path to a LittleFS/SPIFFS filesystem image, either omitted or one per --chip in the same order ("-" to skip a chip)
+    #[arg(long = "filesystem")]
+    filesystem: Vec<String>,
+
+    /// name of the partition the filesystem image is flashed to, either omitted or one per --chip in the same order ("-" to skip a chip)
+    #[arg(long = "filesystem-partition")]
+    filesystem_partition: Vec<String>,
+
+    /// flash size, either omitted or one per --chip in the same order ("-" to use the default of 4mb)
+    #[arg(long = "flash-size")]
+    flash_size: Vec<String>,
+
+    /// flash mode (qio/qout/dio/dout), either omitted or one per --chip in the same order ("-" to skip a chip)
+    #[arg(long = "flash-mode")]
+    flash_mode: Vec<String>,
+
+    /// flash frequency (20m/26m/40m/80m), either omitted or one per --chip in the same order ("-" to skip a chip)
+    #[arg(long = "flash-freq")]
+    flash_freq: Vec<String>,
+
+    /// serve esp-web-tools locally instead of pulling it from unpkg.com; requires --esp-web-tools-js
+    #[arg(long)]
+    offline: bool,
 
-    /// path to bootloader
-    #[arg(short, long)]
-    bootloader: Option<PathBuf>,
+    /// path to a vendored esp-web-tools install-button.js bundle, required by --offline
+    #[arg(long = "esp-web-tools-js")]
+    esp_web_tools_js: Option<PathBuf>,
 
-    /// path to partition table csv
-    #[arg(short, long)]
-    partition_table: Option<PathBuf>,
+    /// enable the post-flash Improv-Wi-Fi provisioning step (firmware must speak Improv serial)
+    #[arg(long)]
+    improv: bool,
+
+    /// seconds to wait for the device to reappear on serial before offering Improv Wi-Fi setup
+    #[arg(long = "improv-wait-time", default_value_t = 10)]
+    improv_wait_time: u32,
+}
+
+static STYLE_CSS: &str = include_str!("../static/style.css");
+static FAVICON_ICO: &[u8] = include_bytes!("../static/favicon.ico");
+
+#[get("/static/install-button.js")]
+fn install_button_js(config: &State<Config>) -> Option<(ContentType, String)> {
+    config
+        .install_button_js
+        .clone()
+        .map(|js| (ContentType::JavaScript, js))
+}
+
+#[get("/static/style.css")]
+fn style_css() -> (ContentType, &'static str) {
+    (ContentType::CSS, STYLE_CSS)
+}
+
+#[get("/favicon.ico")]
+fn favicon() -> (ContentType, &'static [u8]) {
+    (ContentType::Icon, FAVICON_ICO)
+}
+
+#[get("/<idx>/bootloader.bin")]
+fn bootloader(idx: usize, data: &State<Vec<PartsData>>) -> Option<Vec<u8>> {
+    data.get(idx).map(|p| p.bootloader.clone())
+}
 
-    elf: PathBuf,
+#[get("/<idx>/partitions.bin")]
+fn partitions(idx: usize, data: &State<Vec<PartsData>>) -> Option<Vec<u8>> {
+    data.get(idx).map(|p| p.partitions.clone())
 }
 
-#[get("/bootloader.bin")]
-fn bootloader(data: &State<PartsData>) -> Vec<u8> {
-    data.bootloader.clone()
+#[get("/<idx>/firmware.bin")]
+fn firmware(idx: usize, data: &State<Vec<PartsData>>) -> Option<Vec<u8>> {
+    data.get(idx).map(|p| p.firmware.clone())
 }
 
-#[get("/partitions.bin")]
-fn partitions(data: &State<PartsData>) -> Vec<u8> {
-    data.partitions.clone()
+#[get("/<idx>/filesystem.bin")]
+fn filesystem(idx: usize, data: &State<Vec<PartsData>>) -> Option<Vec<u8>> {
+    data.get(idx).and_then(|p| p.filesystem.clone())
 }
 
-#[get("/firmware.bin")]
-fn firmware(data: &State<PartsData>) -> Vec<u8> {
-    data.firmware.clone()
+/// A full-flash binary served as a downloadable attachment rather than inline.
+struct MergedBin {
+    data: Vec<u8>,
+    filename: String,
+}
+
+impl<'r> rocket::response::Responder<'r, 'static> for MergedBin {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        rocket::Response::build_from(self.data.respond_to(req)?)
+            .header(ContentType::Binary)
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.filename),
+            )
+            .ok()
+    }
+}
+
+#[get("/<idx>/merged.bin")]
+fn merged(idx: usize, data: &State<Vec<PartsData>>) -> Option<MergedBin> {
+    data.get(idx).map(|p| MergedBin {
+        data: p.merged.clone(),
+        filename: format!("merged-{idx}-{}.bin", p.chip),
+    })
 }
 
 #[get("/")]
-fn index() -> content::RawHtml<&'static str> {
-    content::RawHtml(
+fn index(config: &State<Config>) -> content::RawHtml<String> {
+    let install_button_src = if config.offline {
+        "/static/install-button.js"
+    } else {
+        "https://unpkg.com/esp-web-tools@8.0.2/dist/web/install-button.js?module"
+    };
+    let favicon_link = if config.offline {
+        "<link rel=\"icon\" href=\"/favicon.ico\">"
+    } else {
+        ""
+    };
+    let stylesheet_link = if config.offline {
+        "<link rel=\"stylesheet\" href=\"/static/style.css\">"
+    } else {
+        ""
+    };
+    let improv_note = if config.improv {
+        "<span><i>After flashing, you'll be offered a Wi-Fi setup step. This only works if the \
+        flashed firmware speaks the Improv Wi-Fi serial protocol.</i></span><br>"
+    } else {
+        ""
+    };
+
+    content::RawHtml(format!(
         "
         <html>
+        <head>
+            {favicon_link}
+            {stylesheet_link}
+        </head>
         <body>
             <center>
                 <h1>ESP Web Flasher</h1>
@@ -54,11 +176,13 @@ fn index() -> content::RawHtml<&'static str> {
                 <div id=\"main\" style=\"display: none;\">
 
                     <br>
-                    <script type=\"module\" src=\"https://unpkg.com/esp-web-tools@8.0.2/dist/web/install-button.js?module\">
+                    <script type=\"module\" src=\"{install_button_src}\">
                     </script>
                     <esp-web-install-button id=\"installButton\" manifest=\"manifest.json\"></esp-web-install-button>
                     <br>
                     <span><i>NOTE: Make sure to close anything using your devices com port (e.g. Serial monitor)</i></span>
+                    <br>
+                    {improv_note}
                 </div>
                 <div id=\"notSupported\" style=\"display: none;\">
                     Your browser does not support the Web Serial API. Try Chrome
@@ -66,132 +190,173 @@ fn index() -> content::RawHtml<&'static str> {
             </center>
 
             <script>
-                if (navigator.serial) {
+                if (navigator.serial) {{
                     document.getElementById(\"notSupported\").style.display = 'none';
                     document.getElementById(\"main\").style.display = 'block';
-                } else {
+                }} else {{
                     document.getElementById(\"notSupported\").style.display = 'block';
                     document.getElementById(\"main\").style.display = 'none';
-                }
+                }}
             </script>
 
         </body>
         </html>
         ",
-    )
+    ))
 }
 
 #[get("/manifest.json")]
-fn manifest() -> content::RawJson<&'static str> {
-    content::RawJson(
-        r#"
-        {
-            "name": "ESP Application",
-            "new_install_prompt_erase": true,
-            "builds": [
-                {
-                "chipFamily": "ESP32",
+fn manifest(data: &State<Vec<PartsData>>, config: &State<Config>) -> content::RawJson<String> {
+    let builds = data
+        .iter()
+        .enumerate()
+        .map(|(idx, p)| {
+            format!(
+                r#"
+                {{
+                "chipFamily": "{chip}",
+                "improv": {improv},
                 "parts": [
-                    {
-                    "path": "bootloader.bin",
-                    "offset": 4096
-                    },
-                    {
-                    "path": "partitions.bin",
-                    "offset": 32768
-                    },
-                    {
-                    "path": "firmware.bin",
-                    "offset": 65536
-                    }
+                    {{
+                    "path": "{idx}/bootloader.bin",
+                    "offset": {bootloader_offset}
+                    }},
+                    {{
+                    "path": "{idx}/partitions.bin",
+                    "offset": {partitions_offset}
+                    }},
+                    {{
+                    "path": "{idx}/firmware.bin",
+                    "offset": {firmware_offset}
+                    }}{filesystem_part}
                 ]
+                }}
+                "#,
+                chip = p.chip,
+                improv = config.improv,
+                bootloader_offset = p.bootloader_offset,
+                partitions_offset = p.partitions_offset,
+                firmware_offset = p.firmware_offset,
+                filesystem_part = match p.filesystem_offset {
+                    Some(offset) => format!(
+                        r#",
+                    {{
+                    "path": "{idx}/filesystem.bin",
+                    "offset": {offset}
+                    }}"#,
+                    ),
+                    None => String::new(),
                 },
-                {
-                "chipFamily": "ESP32-C3",
-                "parts": [
-                    {
-                    "path": "bootloader.bin",
-                    "offset": 0
-                    },
-                    {
-                    "path": "partitions.bin",
-                    "offset": 32768
-                    },
-                    {
-                    "path": "firmware.bin",
-                    "offset": 65536
-                    }
-                ]
-                },
-                {
-                "chipFamily": "ESP32-S2",
-                "parts": [
-                    {
-                    "path": "bootloader.bin",
-                    "offset": 4096
-                    },
-                    {
-                    "path": "partitions.bin",
-                    "offset": 32768
-                    },
-                    {
-                    "path": "firmware.bin",
-                    "offset": 65536
-                    }
-                ]
-                },
-                {
-                "chipFamily": "ESP32-S3",
-                "parts": [
-                    {
-                    "path": "bootloader.bin",
-                    "offset": 0
-                    },
-                    {
-                    "path": "partitions.bin",
-                    "offset": 32768
-                    },
-                    {
-                    "path": "firmware.bin",
-                    "offset": 65536
-                    }
-                ]
-                }
-            ]
-        }
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let improv_wait_time = if config.improv {
+        format!(
+            ",\n            \"new_install_improv_wait_time\": {}",
+            config.improv_wait_time
+        )
+    } else {
+        String::new()
+    };
+
+    content::RawJson(format!(
+        r#"
+        {{
+            "name": "ESP Application",
+            "new_install_prompt_erase": true,
+            "builds": [{builds}]{improv_wait_time}
+        }}
         "#,
-    )
+    ))
+}
+
+struct Config {
+    offline: bool,
+    install_button_js: Option<String>,
+    improv: bool,
+    improv_wait_time: u32,
 }
 
 struct PartsData {
     chip: String,
     bootloader: Vec<u8>,
+    bootloader_offset: u32,
     partitions: Vec<u8>,
+    partitions_offset: u32,
     firmware: Vec<u8>,
+    firmware_offset: u32,
+    filesystem: Option<Vec<u8>>,
+    filesystem_offset: Option<u32>,
+    merged: Vec<u8>,
 }
 
-fn prepare() -> Result<PartsData> {
-    let opts = Args::parse();
+/// Lays out `(offset, data)` segments into a single buffer padded with `0xFF`, matching
+/// esptool's `merge_bin` layout. Later entries in `segments` win where ranges overlap.
+fn merge_segments(segments: &[(usize, &[u8])]) -> Vec<u8> {
+    let merged_size = segments
+        .iter()
+        .map(|(addr, data)| addr + data.len())
+        .max()
+        .unwrap_or(0);
+    let mut merged = vec![0xffu8; merged_size];
+    for (addr, data) in segments {
+        merged[*addr..*addr + data.len()].copy_from_slice(data);
+    }
+    merged
+}
 
-    let elf = std::fs::read(opts.elf)?;
+fn prepare_one(
+    chip: Chip,
+    elf_path: &PathBuf,
+    bootloader_path: Option<&PathBuf>,
+    partition_table_path: Option<&PathBuf>,
+    filesystem_path: Option<&PathBuf>,
+    filesystem_partition_name: Option<&String>,
+    flash_size: FlashSize,
+    flash_mode: Option<FlashMode>,
+    flash_freq: Option<FlashFrequency>,
+) -> Result<PartsData> {
+    let elf = std::fs::read(elf_path)?;
 
-    let p = if let Some(p) = &opts.partition_table {
+    let p = if let Some(p) = partition_table_path {
         Some(PartitionTable::try_from_bytes(std::fs::read(p)?)?)
     } else {
         None
     };
 
-    let b = if let Some(p) = &opts.bootloader {
+    let (filesystem, filesystem_offset) = match (filesystem_path, filesystem_partition_name) {
+        (Some(fs_path), Some(fs_partition)) => {
+            let table = p
+                .as_ref()
+                .ok_or_else(|| anyhow!("--filesystem-partition requires --partition-table"))?;
+            let partition = table
+                .partitions()
+                .into_iter()
+                .find(|part| part.name() == *fs_partition)
+                .ok_or_else(|| {
+                    anyhow!("partition `{fs_partition}` not found in partition table")
+                })?;
+            (Some(std::fs::read(fs_path)?), Some(partition.offset()))
+        }
+        (None, None) => (None, None),
+        (Some(_), None) => return Err(anyhow!("--filesystem requires --filesystem-partition")),
+        (None, Some(_)) => return Err(anyhow!("--filesystem-partition requires --filesystem")),
+    };
+
+    let b = if let Some(p) = bootloader_path {
         Some(std::fs::read(p)?)
     } else {
         None
     };
 
     let firmware = FirmwareImageBuilder::new(&elf)
-        .flash_size(Some(FlashSize::Flash4Mb)) // TODO make configurable
+        .flash_size(Some(flash_size))
+        .flash_mode(flash_mode)
+        .flash_freq(flash_freq)
         .build()?;
 
-    let chip = opts.chip;
     let chip_name = match chip {
         Chip::Esp32 => "ESP32",
         Chip::Esp32c3 => "ESP32-C3",
@@ -200,22 +365,149 @@ fn prepare() -> Result<PartsData> {
         Chip::Esp8266 => "ESP8266",
     };
 
-    let image = chip.get_flash_image(&firmware, b, p, None, None)?;
+    let image = chip.get_flash_image(&firmware, b, p, flash_mode, flash_freq)?;
     let parts: Vec<_> = image.flash_segments().collect();
     let bootloader = &parts[0];
     let partitions = &parts[1];
     let app = &parts[2];
 
+    let filesystem_segment = filesystem
+        .as_ref()
+        .zip(filesystem_offset)
+        .map(|(data, addr)| (addr as usize, data.as_slice()));
+    let segments: Vec<(usize, &[u8])> = parts
+        .iter()
+        .map(|segment| (segment.addr as usize, segment.data.as_ref()))
+        .chain(filesystem_segment)
+        .collect();
+
+    let merged = merge_segments(&segments);
+
     Ok(PartsData {
         chip: chip_name.to_string(),
         bootloader: bootloader.data.to_vec(),
+        bootloader_offset: bootloader.addr,
         partitions: partitions.data.to_vec(),
+        partitions_offset: partitions.addr,
         firmware: app.data.to_vec(),
+        firmware_offset: app.addr,
+        filesystem,
+        filesystem_offset,
+        merged,
     })
 }
 
+/// Resolves a `--chip`-indexed override list: empty means "not given" for every entry, a list of
+/// exactly `total` entries lets each `--chip` opt in or out (via [`SKIP`]) unambiguously, and any
+/// other length is rejected rather than silently applied to a prefix of the builds.
+fn resolve_override<T>(
+    values: &[String],
+    idx: usize,
+    total: usize,
+    name: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Option<T>> {
+    if values.is_empty() {
+        return Ok(None);
+    }
+    if values.len() != total {
+        return Err(anyhow!(
+            "--{name} was given {} time(s) but {total} --chip were given; pass exactly one \
+             --{name} per --chip (use \"{SKIP}\" to skip one) or omit --{name} entirely",
+            values.len()
+        ));
+    }
+    match values[idx].as_str() {
+        SKIP => Ok(None),
+        v => parse(v)
+            .map(Some)
+            .map_err(|e| anyhow!("invalid --{name} value `{v}`: {e}")),
+    }
+}
+
+fn prepare(opts: &Args) -> Result<Vec<PartsData>> {
+    if opts.chip.len() != opts.elf.len() {
+        return Err(anyhow!(
+            "expected one --elf per --chip, got {} --chip and {} --elf",
+            opts.chip.len(),
+            opts.elf.len()
+        ));
+    }
+
+    let total = opts.chip.len();
+
+    opts.chip
+        .iter()
+        .enumerate()
+        .map(|(idx, &chip)| {
+            let bootloader = resolve_override(&opts.bootloader, idx, total, "bootloader", |v| {
+                Ok(PathBuf::from(v))
+            })?;
+            let partition_table =
+                resolve_override(&opts.partition_table, idx, total, "partition-table", |v| {
+                    Ok(PathBuf::from(v))
+                })?;
+            let filesystem = resolve_override(&opts.filesystem, idx, total, "filesystem", |v| {
+                Ok(PathBuf::from(v))
+            })?;
+            let filesystem_partition = resolve_override(
+                &opts.filesystem_partition,
+                idx,
+                total,
+                "filesystem-partition",
+                |v| Ok(v.to_string()),
+            )?;
+            let flash_size = resolve_override(&opts.flash_size, idx, total, "flash-size", |v| {
+                FlashSize::from_str(v, true)
+            })?
+            .unwrap_or(FlashSize::Flash4Mb);
+            let flash_mode = resolve_override(&opts.flash_mode, idx, total, "flash-mode", |v| {
+                FlashMode::from_str(v, true)
+            })?;
+            let flash_freq = resolve_override(&opts.flash_freq, idx, total, "flash-freq", |v| {
+                FlashFrequency::from_str(v, true)
+            })?;
+
+            prepare_one(
+                chip,
+                &opts.elf[idx],
+                bootloader.as_ref(),
+                partition_table.as_ref(),
+                filesystem.as_ref(),
+                filesystem_partition.as_ref(),
+                flash_size,
+                flash_mode,
+                flash_freq,
+            )
+        })
+        .collect()
+}
+
 fn main() -> Result<()> {
-    let data = prepare()?;
+    let opts = Args::parse();
+    let data = prepare(&opts)?;
+
+    let install_button_js =
+        match (&opts.offline, &opts.esp_web_tools_js) {
+            (true, Some(path)) => Some(std::fs::read_to_string(path).map_err(|e| {
+                anyhow!("failed to read --esp-web-tools-js bundle at {path:?}: {e}")
+            })?),
+            (true, None) => {
+                return Err(anyhow!(
+                    "--offline requires --esp-web-tools-js <path>, pointing at a vendored copy of \
+                 esp-web-tools' install-button.js (e.g. fetched from \
+                 https://unpkg.com/esp-web-tools@8.0.2/dist/web/install-button.js)"
+                ))
+            }
+            (false, _) => None,
+        };
+
+    let config = Config {
+        offline: opts.offline,
+        install_button_js,
+        improv: opts.improv,
+        improv_wait_time: opts.improv_wait_time,
+    };
 
     std::thread::spawn(|| {
         std::thread::sleep(Duration::from_millis(1000));
@@ -226,9 +518,21 @@ fn main() -> Result<()> {
         let _res = rocket::build()
             .mount(
                 "/",
-                routes![index, manifest, bootloader, partitions, firmware],
+                routes![
+                    index,
+                    manifest,
+                    bootloader,
+                    partitions,
+                    firmware,
+                    filesystem,
+                    merged,
+                    install_button_js,
+                    style_css,
+                    favicon,
+                ],
             )
             .manage(data)
+            .manage(config)
             .launch()
             .await
             .expect("Problem launching server");
@@ -236,3 +540,37 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::merge_segments;
+
+    #[test]
+    fn pads_gaps_between_segments_with_0xff() {
+        let segments = [(0, &[0xaa, 0xbb][..]), (4, &[0xcc, 0xdd][..])];
+        assert_eq!(
+            merge_segments(&segments),
+            vec![0xaa, 0xbb, 0xff, 0xff, 0xcc, 0xdd]
+        );
+    }
+
+    #[test]
+    fn appends_a_filesystem_segment_past_the_firmware() {
+        let segments = [
+            (0, &[0x01][..]),
+            (1, &[0x02, 0x03][..]),
+            (10, &[0xaa, 0xbb][..]),
+        ];
+        let merged = merge_segments(&segments);
+        assert_eq!(merged.len(), 12);
+        assert_eq!(&merged[0..3], &[0x01, 0x02, 0x03]);
+        assert_eq!(&merged[3..10], &[0xff; 7]);
+        assert_eq!(&merged[10..12], &[0xaa, 0xbb]);
+    }
+
+    #[test]
+    fn overlapping_segments_let_the_later_one_win_without_panicking() {
+        let segments = [(0, &[0x11, 0x11, 0x11][..]), (1, &[0x22, 0x22][..])];
+        assert_eq!(merge_segments(&segments), vec![0x11, 0x22, 0x22]);
+    }
+}